@@ -1,17 +1,25 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
     fs::{self, File},
     io::{BufReader, Read, Write},
     net::SocketAddr,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
 use anyhow::Error;
 use clap::Parser;
+use filetime::{set_file_mtime, FileTime};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::timeout,
 };
 use walkdir::WalkDir;
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 /// Post directory to another host.
 /// i.e. send and receive directory content via socket.
@@ -39,9 +47,42 @@ struct Args {
     /// reverse mode
     #[arg(short, long, default_value_t = false)]
     reverse: bool,
+
+    /// pre-shared access key (alphanumeric, 8+ chars) required before transfer
+    #[arg(short, long, default_value = "")]
+    key: String,
+
+    /// encrypt the transfer with an ephemeral X25519 exchange + AES-256-GCM
+    #[arg(short, long, default_value_t = false)]
+    encrypt: bool,
+
+    /// compare file hashes (not just sizes) when skipping already-present files;
+    /// only the sending side's flag counts, the receiver adopts it
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// use a reliable UDP transport instead of TCP (for high-latency links)
+    #[arg(short, long, default_value_t = false)]
+    udp: bool,
+
+    /// number of datagrams kept in flight before waiting for acknowledgements (UDP only)
+    #[arg(short, long, default_value_t = 64)]
+    window: usize,
+
+    /// proposed transfer chunk size in bytes (negotiated down to a sane maximum)
+    #[arg(short, long, default_value_t = BUFFER_SIZE)]
+    chunk_size: usize,
 }
 
+// default chunk size (a proposal of this value is accepted unchanged); the
+// receiver clamps any proposal to the range 1..=MAX_CHUNK_SIZE.
 const BUFFER_SIZE: usize = 1024;
+const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+// fixed UDP datagram payload size, and how many consecutive receive timeouts the
+// UDP channel tolerates before failing
+const TRANSFER_BUFFER_SIZE: usize = 1024;
+const MAX_RETRIES: usize = 16;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -86,7 +127,19 @@ NB: empty filename indicates end of transfer
 async fn client(args: Args, sending_mode: bool) -> Result<(), Error> {
     let path = Path::new(&args.directory);
     let dir = validate_path(path)?;
-    let stream = TcpStream::connect(format!("{}:{}", args.ip_host, args.port)).await?;
+    let transport = if args.udp {
+        Transport::udp_client(&args.ip_host, args.port, args.window).await?
+    } else {
+        Transport::Tcp(TcpStream::connect(format!("{}:{}", args.ip_host, args.port)).await?)
+    };
+    let mut conn = Conn::new(transport);
+
+    // optional encryption is negotiated first, so even the key handshake is protected
+    if args.encrypt {
+        conn.establish_encryption().await?;
+    }
+    // the connecting side proves its identity before any transfer
+    send_key(&mut conn, &args.key).await?;
 
     println!("----------------------------------------");
     println!(
@@ -96,9 +149,9 @@ async fn client(args: Args, sending_mode: bool) -> Result<(), Error> {
     );
     println!("----------------------------------------");
     if sending_mode {
-        directory_send(dir, stream).await?;
+        directory_send(dir, conn, args.resume, args.chunk_size).await?;
     } else {
-        directory_receive(dir, stream).await?;
+        directory_receive(dir, conn, args.resume).await?;
     };
     Ok(())
 }
@@ -107,6 +160,31 @@ async fn server(args: Args, sending_mode: bool) -> Result<(), Error> {
     let path = Path::new(&args.directory);
     let dir = validate_path(path)?;
 
+    // UDP serves a single peer and returns when that transfer ends, rather than
+    // spawning per accepted socket
+    if args.udp {
+        println!("----------------------------------------");
+        println!("start  listening on: {} (udp)", args.port);
+        println!(
+            "{} directory: {}",
+            if sending_mode { "sending  " } else { "receiving" },
+            dir
+        );
+        println!("----------------------------------------");
+
+        let transport = Transport::udp_server(args.port, args.window).await?;
+        let mut conn = Conn::new(transport);
+        if args.encrypt {
+            conn.establish_encryption().await?;
+        }
+        check_key(&mut conn, &args.key).await?;
+        return if sending_mode {
+            directory_send(dir, conn, args.resume, args.chunk_size).await
+        } else {
+            directory_receive(dir, conn, args.resume).await
+        };
+    }
+
     let listener = create_listener(args.port).await?;
     println!("----------------------------------------");
     println!("start  listening on: {}", args.port);
@@ -124,15 +202,44 @@ async fn server(args: Args, sending_mode: bool) -> Result<(), Error> {
     loop {
         let (stream, addr) = listener_accept_connection(&listener).await?;
         println!("new connection from: {}", addr);
-        tokio::spawn(connection_handler(sending_mode, dir.clone(), stream));
+        tokio::spawn(connection_handler(
+            sending_mode,
+            dir.clone(),
+            args.key.clone(),
+            args.encrypt,
+            args.resume,
+            args.chunk_size,
+            stream,
+        ));
     }
 }
 
-async fn connection_handler(sending_mode: bool, dir: String, stream: TcpStream) {
+async fn connection_handler(
+    sending_mode: bool,
+    dir: String,
+    key: String,
+    encrypt: bool,
+    resume: bool,
+    chunk_size: usize,
+    stream: TcpStream,
+) {
+    let mut conn = Conn::new(Transport::Tcp(stream));
+    if encrypt {
+        if let Err(error) = conn.establish_encryption().await {
+            println!("rejected connection: {}", error);
+            return;
+        }
+    }
+    // authenticate the peer before touching the filesystem; on failure the peer has
+    // been NAKed, so log the rejection and drop the connection
+    if let Err(error) = check_key(&mut conn, &key).await {
+        println!("rejected connection: {}", error);
+        return;
+    }
     let result = if sending_mode {
-        directory_send(dir, stream).await
+        directory_send(dir, conn, resume, chunk_size).await
     } else {
-        directory_receive(dir, stream).await
+        directory_receive(dir, conn, resume).await
     };
     if let Err(error) = result {
         println!("ERROR: {}", error);
@@ -168,28 +275,124 @@ async fn listener_accept_connection(
     }
 }
 
-async fn directory_send(dir: String, mut stream: TcpStream) -> Result<(), Error> {
+// a single file advertised in the pre-transfer manifest
+struct ManifestEntry {
+    full_name: String,
+    relative_name: String,
+    size: u64,
+    hash: String,
+}
+
+async fn directory_send(
+    dir: String,
+    mut conn: Conn,
+    resume: bool,
+    chunk_size: usize,
+) -> Result<(), Error> {
+    // agree on a chunk size before anything else moves over the wire
+    conn.propose_chunk_size(chunk_size).await?;
+    // the sender dictates whether the manifest carries hashes, so both ends frame
+    // the entries identically regardless of the receiver's own `--resume` flag
+    conn.send_resume(resume).await?;
+    // negotiate the permission/mtime block once so a metadata-unaware receiver interops
+    conn.propose_metadata().await?;
     let prefix_len = dir.len();
-    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()) {
         if entry.path().is_file() {
-            let full_file_name = entry.path().display().to_string();
-            let relative_file_name = &full_file_name[prefix_len..];
-            if !relative_file_name.is_empty() {
-                print!("sending: {} ... ", relative_file_name);
-                write_file(&full_file_name, relative_file_name, &mut stream).await?;
-                println!("DONE");
+            let full_name = entry.path().display().to_string();
+            let relative_name = full_name[prefix_len..].to_string();
+            if !relative_name.is_empty() {
+                let size = fs::metadata(&full_name)?.len();
+                let hash = if resume {
+                    hash_file(&full_name)?
+                } else {
+                    String::new()
+                };
+                entries.push(ManifestEntry {
+                    full_name,
+                    relative_name,
+                    size,
+                    hash,
+                });
             }
         }
     }
+
+    // advertise the whole tree, then let the receiver pick what it still needs
+    conn.write_u64(entries.len() as u64).await?;
+    for entry in &entries {
+        conn.write_string(&normalize_name(entry.relative_name.clone()))
+            .await?;
+        conn.write_u64(entry.size).await?;
+        if resume {
+            conn.write_string(&entry.hash).await?;
+        }
+    }
+    let requested = read_index_list(&mut conn).await?;
+    println!("sending {} of {} files", requested.len(), entries.len());
+
+    for index in requested {
+        let entry = entries.get(index as usize).ok_or_else(|| {
+            Error::msg(format!("peer requested out-of-range file index {}", index))
+        })?;
+        print!("sending: {} ... ", entry.relative_name);
+        write_file(&entry.full_name, &entry.relative_name, &mut conn).await?;
+        println!("DONE");
+    }
     // send 0 to indicate end of transfer
-    write_u64(&mut stream, 0).await?;
+    conn.write_u64(0).await?;
+    // make sure the end marker (and any trailing datagrams) are acknowledged before
+    // the socket drops, otherwise a lost tail datagram would strand the receiver
+    conn.flush().await?;
     println!("DONE");
     Ok(())
 }
 
-async fn directory_receive(dir: String, mut stream: TcpStream) -> Result<(), Error> {
+async fn directory_receive(dir: String, mut conn: Conn, _resume: bool) -> Result<(), Error> {
+    // accept (and clamp) the sender's proposed chunk size
+    conn.accept_chunk_size().await?;
+    // the sender negotiates resume; adopt it so the manifest framing matches
+    let resume = conn.recv_resume().await?;
+    // agree on whether each file carries a permission/mtime block
+    conn.accept_metadata().await?;
+    // read the sender's manifest and request only what is missing or out of date
+    let count = conn.read_u64().await?;
+    let mut requested = Vec::new();
+    for index in 0..count {
+        let relative = denormalize_name(conn.read_string().await?);
+        let size = conn.read_u64().await?;
+        let hash = if resume {
+            conn.read_string().await?
+        } else {
+            String::new()
+        };
+        let mut full_name = dir.clone();
+        full_name.push_str(&relative);
+        let needed = match fs::metadata(&full_name) {
+            Err(_) => true,
+            Ok(metadata) => {
+                if metadata.len() != size {
+                    true
+                } else if resume {
+                    hash_file(&full_name).map(|h| h != hash).unwrap_or(true)
+                } else {
+                    false
+                }
+            }
+        };
+        if needed {
+            requested.push(index);
+        }
+    }
+    conn.write_u64(requested.len() as u64).await?;
+    for index in &requested {
+        conn.write_u64(*index).await?;
+    }
+    println!("requesting {} of {} files", requested.len(), count);
+
     loop {
-        let has_next = save_file(&dir, &mut stream).await?;
+        let has_next = save_file(&dir, &mut conn).await?;
         if !has_next {
             println!("DONE");
             break;
@@ -198,128 +401,784 @@ async fn directory_receive(dir: String, mut stream: TcpStream) -> Result<(), Err
     Ok(())
 }
 
-async fn save_file(dir: &String, stream: &mut TcpStream) -> Result<bool, Error> {
-    let file_name = read_string(stream).await?;
+// read a length-prefixed list of u64 indices sent by the receiver
+async fn read_index_list(conn: &mut Conn) -> Result<Vec<u64>, Error> {
+    let count = conn.read_u64().await?;
+    let mut list = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        list.push(conn.read_u64().await?);
+    }
+    Ok(list)
+}
+
+// SHA-256 of a file's contents, rendered as lowercase hex for manifest comparison
+fn hash_file(file_name: &str) -> Result<String, Error> {
+    let mut file = File::open(file_name)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; BUFFER_SIZE];
+    loop {
+        let read_bytes = file.read(&mut buffer)?;
+        if read_bytes == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read_bytes]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+async fn save_file(dir: &String, conn: &mut Conn) -> Result<bool, Error> {
+    let file_name = conn.read_string().await?;
     if file_name.is_empty() {
         return Ok(false);
     }
     let name = denormalize_name(file_name);
     let mut full_name = dir.to_owned();
     full_name.push_str(&name);
-    let mut len = read_u64(stream).await?;
+    let mut len = conn.read_u64().await?;
+    // the metadata block is present only when negotiated at setup; an unrecognized
+    // marker then means the stream is out of sync, so fail loudly rather than
+    // consuming file bytes as the marker and truncating the file.
+    let (mode, mtime) = if conn.metadata {
+        let flag = conn.read_u64().await?;
+        if flag != META_VERSION {
+            return Err(Error::msg(format!(
+                "unsupported metadata version {} for {}",
+                flag, full_name
+            )));
+        }
+        (conn.read_u64().await? as u32, conn.read_u64().await?)
+    } else {
+        (0, 0)
+    };
     print!("writing: {}...", full_name);
 
+    // reject any `..` component before touching the filesystem; the leading path
+    // separator is part of the root-relative naming convention and is expected.
+    if Path::new(&name)
+        .components()
+        .any(|c| matches!(c, Component::ParentDir))
+    {
+        return Err(Error::msg(format!("rejected path traversal: {}", name)));
+    }
+    let root = fs::canonicalize(dir)?;
     {
         // check if folder exists
         let mut path = PathBuf::from(&full_name);
         path.pop();
         if !path.exists() {
-            fs::create_dir_all(path)?;
+            fs::create_dir_all(&path)?;
+        }
+        // the resolved parent must still live under the served root
+        let parent = fs::canonicalize(&path)?;
+        if !parent.starts_with(&root) {
+            return Err(Error::msg(format!(
+                "rejected path outside served directory: {}",
+                full_name
+            )));
+        }
+    }
+    // the parent is known-contained, but `File::create` follows a symlink at the
+    // final component and would write through it outside the root. Refuse a
+    // pre-existing symlink at the target name before opening it.
+    if let Ok(meta) = fs::symlink_metadata(&full_name) {
+        if meta.file_type().is_symlink() {
+            return Err(Error::msg(format!(
+                "rejected symlink target: {}",
+                full_name
+            )));
         }
     }
     // open file
     let mut file = std::fs::File::create(&full_name)?;
     // write content
-    let mut buffer = [0; BUFFER_SIZE];
     while len > 0 {
-        if len > BUFFER_SIZE as u64 {
-            stream.read_exact(&mut buffer).await?;
-            let _ = file.write(&buffer)?;
-            len -= BUFFER_SIZE as u64;
-        } else {
-            let chunk = read_chunk(stream, len as usize).await?;
-            let _ = file.write(&chunk)?;
-            len -= chunk.len() as u64;
-        }
+        let chunk = conn.read_content_chunk(len).await?;
+        let _ = file.write(&chunk)?;
+        len -= chunk.len() as u64;
     }
+    // close the handle before stamping permissions and modification time
+    drop(file);
+    apply_metadata(&full_name, mode, mtime)?;
     println!("DONE");
     Ok(true)
 }
 
-async fn read_u64(stream: &mut TcpStream) -> Result<u64, Error> {
-    let mut len_bytes = [0; 8];
-    stream.read_exact(&mut len_bytes).await?;
-    let str_len: u64 = u64::from_be_bytes(len_bytes);
-    Ok(str_len)
+// metadata protocol marker written after the file size; the receiver rejects any
+// other value as a stream desync. A transmitted mode of 0 means "leave default".
+const META_VERSION: u64 = 1;
+
+async fn write_file(file_name: &str, remote_name: &str, conn: &mut Conn) -> Result<(), Error> {
+    let file = File::open(file_name)?;
+    let metadata = file.metadata()?;
+    let mut len = metadata.len();
+    let mode = file_mode(&metadata);
+    let mtime = file_mtime(&metadata);
+    let mut reader = BufReader::new(file);
+    let normalized_name = normalize_name(remote_name.to_string());
+    // write normalized filename
+    conn.write_string(&normalized_name).await?;
+    // write the length
+    conn.write_u64(len).await?;
+    // write the metadata block only when the receiver negotiated support for it
+    // (mode is 0 on platforms without Unix permissions)
+    if conn.metadata {
+        conn.write_u64(META_VERSION).await?;
+        conn.write_u64(mode as u64).await?;
+        conn.write_u64(mtime).await?;
+    }
+    // write chunks using the negotiated chunk size
+    let mut buffer = vec![0u8; conn.chunk_size];
+    while len > 0 {
+        let read_bytes = reader.read(&mut buffer)? as u64;
+        conn.write_buffer(&buffer, read_bytes).await?;
+        len -= read_bytes;
+    }
+    Ok(())
+}
+
+// one-byte handshake replies exchanged after the key check
+const ACK: u8 = 1;
+const NAK: u8 = 0;
+
+// an empty key means the peer opted out of authentication entirely; a configured
+// key must follow the documented "alphanumeric, 8+ chars" rule.
+fn validate_key(key: &str) -> Result<(), Error> {
+    if key.len() < 8 || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::msg(
+            "access key must be at least 8 alphanumeric characters",
+        ));
+    }
+    Ok(())
+}
+
+// for equal lengths the time does not depend on where the bytes differ, so a peer
+// cannot learn the key's content by timing; a length mismatch returns at once
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    diff == 0
 }
 
-async fn read_chunk(stream: &mut TcpStream, chunk_len: usize) -> Result<Vec<u8>, Error> {
-    if chunk_len > 0 {
-        let mut frame_data = vec![0; chunk_len];
-        stream.read_exact(&mut frame_data).await?;
-        Ok(frame_data)
+// connecting side: announce whether we carry a key, present it if so, and wait
+// for the peer's verdict. The presence flag goes first so each side can reconcile
+// its own requirement rather than inferring auth from its own config alone.
+async fn send_key(conn: &mut Conn, key: &str) -> Result<(), Error> {
+    if !key.is_empty() {
+        validate_key(key)?;
+    }
+    let offering = !key.is_empty();
+    conn.stream.write_all(&[offering as u8]).await?;
+    if offering {
+        conn.write_string(key).await?;
+    }
+    let mut reply = [0; 1];
+    conn.stream.read_exact(&mut reply).await?;
+    if reply[0] == ACK {
+        Ok(())
     } else {
-        Err(Error::msg("reading zero buffer is not allowed"))
+        let reason = conn.read_string().await?;
+        Err(Error::msg(format!("authentication rejected: {}", reason)))
     }
 }
 
-async fn read_string(stream: &mut TcpStream) -> Result<String, Error> {
-    let str_len = read_u64(stream).await?;
-    if str_len == 0 {
-        Ok(String::new())
-    } else if str_len < 4096 {
-        let vec = read_chunk(stream, str_len as usize).await?;
-        let str = String::from_utf8(vec)?;
-        Ok(str)
+// listening side: read the peer's presence flag first, then the key if offered,
+// and answer ACK or NAK. A peer that will not present a required key is cleanly
+// NAKed and dropped instead of desyncing the stream against the next real frame.
+async fn check_key(conn: &mut Conn, key: &str) -> Result<(), Error> {
+    if !key.is_empty() {
+        validate_key(key)?;
+    }
+    let mut flag = [0; 1];
+    conn.stream.read_exact(&mut flag).await?;
+    let offered = if flag[0] != 0 {
+        Some(conn.read_string().await?)
     } else {
-        Err(Error::msg(format!(
-            "string should not be longer than 4096 ({})",
-            str_len
-        )))
+        None
+    };
+    // an empty configured key means we accept anyone, regardless of what was sent
+    if key.is_empty() {
+        conn.stream.write_all(&[ACK]).await?;
+        return Ok(());
+    }
+    match offered {
+        Some(offered) if constant_time_eq(offered.as_bytes(), key.as_bytes()) => {
+            conn.stream.write_all(&[ACK]).await?;
+            Ok(())
+        }
+        _ => {
+            conn.stream.write_all(&[NAK]).await?;
+            conn.write_string("invalid access key").await?;
+            Err(Error::msg("invalid access key"))
+        }
     }
 }
 
-async fn write_u64(stream: &mut TcpStream, data: u64) -> Result<(), Error> {
-    let len_bytes = data.to_be_bytes();
-    stream.write_all(&len_bytes).await?;
-    Ok(())
+// per-session AES-256-GCM state; the 4-byte prefix (derived from the shared
+// secret, with a per-direction bit) plus the monotonic counter keep every send
+// nonce unique within the session.
+struct Crypto {
+    cipher: Aes256Gcm,
+    send_prefix: [u8; 4],
+    send_counter: u64,
 }
 
-pub async fn write_buffer(
-    stream: &mut TcpStream,
-    data: &[u8; BUFFER_SIZE],
-    data_len: u64,
-) -> Result<(), Error> {
-    if data_len > 0 {
-        let len = data_len as usize;
-        stream.write_all(&data[..len]).await?;
+// a connection plus the optional symmetric cipher negotiated for it; all framed
+// reads/writes go through here so encryption is transparent to the transfer code.
+struct Conn {
+    stream: Transport,
+    crypto: Option<Crypto>,
+    chunk_size: usize,
+    metadata: bool,
+}
+
+impl Conn {
+    fn new(stream: Transport) -> Self {
+        Conn {
+            stream,
+            crypto: None,
+            chunk_size: BUFFER_SIZE,
+            metadata: false,
+        }
+    }
+
+    // sending side: offer a chunk size and adopt whatever the receiver agreed to
+    async fn propose_chunk_size(&mut self, proposal: usize) -> Result<(), Error> {
+        self.write_u64(proposal as u64).await?;
+        let agreed = self.read_u64().await? as usize;
+        self.chunk_size = agreed.clamp(1, MAX_CHUNK_SIZE);
+        Ok(())
+    }
+
+    // sending side: the sender owns the resume setting so the manifest framing
+    // stays in sync even if the two users pass mismatched `--resume` flags.
+    async fn send_resume(&mut self, resume: bool) -> Result<(), Error> {
+        self.write_u64(resume as u64).await?;
+        Ok(())
+    }
+
+    // receiving side: adopt whatever the sender negotiated
+    async fn recv_resume(&mut self) -> Result<bool, Error> {
+        Ok(self.read_u64().await? != 0)
+    }
+
+    // sending side: offer the permission/mtime block and remember whether the
+    // receiver understands it; a metadata-unaware peer replies 0 and we skip it.
+    async fn propose_metadata(&mut self) -> Result<(), Error> {
+        self.write_u64(1).await?;
+        self.metadata = self.read_u64().await? != 0;
+        Ok(())
+    }
+
+    // receiving side: enable the block only when the sender offered it, echoing
+    // the agreed capability back so both ends frame each file identically.
+    async fn accept_metadata(&mut self) -> Result<(), Error> {
+        let offered = self.read_u64().await? != 0;
+        self.metadata = offered;
+        self.write_u64(offered as u64).await?;
+        Ok(())
+    }
+
+    // receiving side: clamp the proposal to a sane maximum and echo the agreed value
+    async fn accept_chunk_size(&mut self) -> Result<(), Error> {
+        let proposal = self.read_u64().await? as usize;
+        let agreed = proposal.clamp(1, MAX_CHUNK_SIZE);
+        self.write_u64(agreed as u64).await?;
+        self.chunk_size = agreed;
         Ok(())
-    } else {
-        Err(Error::msg("writing zero buffer is not allowed"))
     }
+
+    // ephemeral X25519 exchange: each side sends its 32-byte public key length
+    // prefixed, the shared secret is hashed to a 256-bit AES key.
+    async fn establish_encryption(&mut self) -> Result<(), Error> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        self.stream.write_all(&32u64.to_be_bytes()).await?;
+        self.stream.write_all(public.as_bytes()).await?;
+
+        let mut len_bytes = [0; 8];
+        self.stream.read_exact(&mut len_bytes).await?;
+        if u64::from_be_bytes(len_bytes) != 32 {
+            return Err(Error::msg("unexpected public key length"));
+        }
+        let mut peer_bytes = [0u8; 32];
+        self.stream.read_exact(&mut peer_bytes).await?;
+        let peer_public = PublicKey::from(peer_bytes);
+
+        let shared = secret.diffie_hellman(&peer_public);
+        let key = Sha256::digest(shared.as_bytes());
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| Error::msg("failed to build cipher from shared secret"))?;
+
+        // Both sides derive the same AES key, so the two directions must never
+        // reuse a nonce. Derive the 4-byte nonce prefix from the shared secret
+        // (not from independent randomness, which could collide) and fold in a
+        // direction bit keyed on whose public key sorts higher, guaranteeing the
+        // two sending prefixes differ within the session.
+        let mut prefix_hash = Sha256::new();
+        prefix_hash.update(b"post_rs nonce prefix");
+        prefix_hash.update(shared.as_bytes());
+        let digest = prefix_hash.finalize();
+        let mut send_prefix = [0u8; 4];
+        send_prefix.copy_from_slice(&digest[..4]);
+        if public.as_bytes().as_slice() > peer_bytes.as_slice() {
+            send_prefix[0] |= 0x01;
+        } else {
+            send_prefix[0] &= 0xfe;
+        }
+
+        self.crypto = Some(Crypto {
+            cipher,
+            send_prefix,
+            send_counter: 0,
+        });
+        Ok(())
+    }
+
+    // encrypt a frame: [u64 ciphertext_len][12-byte nonce][ciphertext || tag]
+    async fn write_frame(&mut self, plaintext: &[u8]) -> Result<(), Error> {
+        let Conn { stream, crypto, .. } = self;
+        let crypto = crypto.as_mut().expect("write_frame without cipher");
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&crypto.send_prefix);
+        nonce[4..].copy_from_slice(&crypto.send_counter.to_be_bytes());
+        crypto.send_counter += 1;
+        let ciphertext = crypto
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::msg("encryption failed"))?;
+        stream
+            .write_all(&(ciphertext.len() as u64).to_be_bytes())
+            .await?;
+        stream.write_all(&nonce).await?;
+        stream.write_all(&ciphertext).await?;
+        Ok(())
+    }
+
+    // read and decrypt a single frame, failing on any GCM tag mismatch
+    async fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let Conn { stream, crypto, .. } = self;
+        let crypto = crypto.as_mut().expect("read_frame without cipher");
+        let mut len_bytes = [0; 8];
+        stream.read_exact(&mut len_bytes).await?;
+        let ct_len = u64::from_be_bytes(len_bytes) as usize;
+        let mut nonce = [0u8; 12];
+        stream.read_exact(&mut nonce).await?;
+        let mut ciphertext = vec![0; ct_len];
+        stream.read_exact(&mut ciphertext).await?;
+        crypto
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .map_err(|_| Error::msg("GCM tag verification failed"))
+    }
+
+    async fn read_u64(&mut self) -> Result<u64, Error> {
+        if self.crypto.is_some() {
+            let bytes = self.read_frame().await?;
+            if bytes.len() != 8 {
+                return Err(Error::msg("malformed u64 frame"));
+            }
+            let mut len_bytes = [0; 8];
+            len_bytes.copy_from_slice(&bytes);
+            Ok(u64::from_be_bytes(len_bytes))
+        } else {
+            let mut len_bytes = [0; 8];
+            self.stream.read_exact(&mut len_bytes).await?;
+            Ok(u64::from_be_bytes(len_bytes))
+        }
+    }
+
+    async fn write_u64(&mut self, data: u64) -> Result<(), Error> {
+        if self.crypto.is_some() {
+            self.write_frame(&data.to_be_bytes()).await
+        } else {
+            self.stream.write_all(&data.to_be_bytes()).await?;
+            Ok(())
+        }
+    }
+
+    async fn read_chunk(&mut self, chunk_len: usize) -> Result<Vec<u8>, Error> {
+        if chunk_len == 0 {
+            return Err(Error::msg("reading zero buffer is not allowed"));
+        }
+        if self.crypto.is_some() {
+            let data = self.read_frame().await?;
+            if data.len() != chunk_len {
+                return Err(Error::msg("decrypted chunk length mismatch"));
+            }
+            Ok(data)
+        } else {
+            let mut frame_data = vec![0; chunk_len];
+            self.stream.read_exact(&mut frame_data).await?;
+            Ok(frame_data)
+        }
+    }
+
+    // read the next slice of a file body, returning however many bytes the sender
+    // framed (capped at the negotiated chunk size in cleartext mode).
+    async fn read_content_chunk(&mut self, remaining: u64) -> Result<Vec<u8>, Error> {
+        if self.crypto.is_some() {
+            self.read_frame().await
+        } else {
+            let take = remaining.min(self.chunk_size as u64) as usize;
+            let mut frame_data = vec![0; take];
+            self.stream.read_exact(&mut frame_data).await?;
+            Ok(frame_data)
+        }
+    }
+
+    async fn read_string(&mut self) -> Result<String, Error> {
+        let str_len = self.read_u64().await?;
+        if str_len == 0 {
+            Ok(String::new())
+        } else if str_len < 4096 {
+            let vec = self.read_chunk(str_len as usize).await?;
+            let str = String::from_utf8(vec)?;
+            Ok(str)
+        } else {
+            Err(Error::msg(format!(
+                "string should not be longer than 4096 ({})",
+                str_len
+            )))
+        }
+    }
+
+    async fn write_buffer(&mut self, data: &[u8], data_len: u64) -> Result<(), Error> {
+        if data_len == 0 {
+            return Err(Error::msg("writing zero buffer is not allowed"));
+        }
+        let len = data_len as usize;
+        if self.crypto.is_some() {
+            self.write_frame(&data[..len]).await
+        } else {
+            self.stream.write_all(&data[..len]).await?;
+            Ok(())
+        }
+    }
+
+    async fn write_string(&mut self, message: &str) -> Result<(), Error> {
+        let data = message.as_bytes();
+        let len = data.len();
+        if len == 0 {
+            return Err(Error::msg("writing empty string is not allowed"));
+        }
+        self.write_u64(len as u64).await?;
+        if self.crypto.is_some() {
+            self.write_frame(data).await
+        } else {
+            self.stream.write_all(data).await?;
+            Ok(())
+        }
+    }
+
+    // flush the underlying transport (waits for UDP acknowledgements, no-op on TCP)
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush().await
+    }
+}
+
+// byte-moving layer beneath the framing: either a TCP stream or a reliable
+// UDP channel. Only this layer differs between the two transport modes.
+enum Transport {
+    Tcp(TcpStream),
+    Udp(ReliableUdp),
+}
+
+impl Transport {
+    // open a UDP channel to the listening peer and send the hello datagram; no
+    // reply is awaited
+    async fn udp_client(ip_host: &str, port: u16, window: usize) -> Result<Transport, Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(format!("{}:{}", ip_host, port)).await?;
+        // announce ourselves so the server learns our address before any data flows
+        socket.send(&[HELLO]).await?;
+        Ok(Transport::Udp(ReliableUdp::new(socket, None, window)))
+    }
+
+    // wait for a peer's hello datagram, then pin the channel to that address
+    async fn udp_server(port: u16, window: usize) -> Result<Transport, Error> {
+        let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+        let mut buffer = [0u8; 1];
+        let (_len, peer) = socket.recv_from(&mut buffer).await?;
+        println!("new connection from: {}", peer);
+        socket.connect(peer).await?;
+        Ok(Transport::Udp(ReliableUdp::new(socket, Some(peer), window)))
+    }
+
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        match self {
+            Transport::Tcp(stream) => {
+                stream.read_exact(buffer).await?;
+                Ok(())
+            }
+            Transport::Udp(udp) => udp.read_exact(buffer).await,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Transport::Tcp(stream) => {
+                stream.write_all(data).await?;
+                Ok(())
+            }
+            Transport::Udp(udp) => udp.write_all(data).await,
+        }
+    }
+
+    // wait for outstanding datagrams to be acknowledged; a no-op on TCP
+    async fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Transport::Tcp(_) => Ok(()),
+            Transport::Udp(udp) => udp.flush().await,
+        }
+    }
+}
+
+// UDP datagram kinds
+const HELLO: u8 = 3;
+const DATA: u8 = 0;
+const DATA_ACK: u8 = 1;
+const DATA_NAK: u8 = 2;
+
+// A reliable, in-order byte channel over UDP. Outgoing bytes are split into
+// `TRANSFER_BUFFER_SIZE` datagrams, each tagged with a monotonically increasing
+// 8-byte sequence index. The receiver reassembles them in order, acknowledges the
+// highest contiguous index, and NAKs gaps. The sender resends a NAKed datagram
+// at once and resends everything outstanding after each 200 ms receive timeout;
+// the channel fails after more than `MAX_RETRIES` consecutive timeouts with no
+// datagram from the peer. NAK-driven resends are not counted against that bound.
+struct ReliableUdp {
+    socket: UdpSocket,
+    window: usize,
+    // send side
+    next_seq: u64,
+    outgoing: BTreeMap<u64, Vec<u8>>,
+    // receive side
+    recv_base: u64,
+    reorder: BTreeMap<u64, Vec<u8>>,
+    inbox: VecDeque<u8>,
 }
 
-async fn write_string(stream: &mut TcpStream, message: &str) -> Result<(), Error> {
-    let data = message.as_bytes();
-    let len = data.len();
-    if len > 0 {
-        write_u64(stream, len as u64).await?;
-        stream.write_all(data).await?;
+impl ReliableUdp {
+    fn new(socket: UdpSocket, _peer: Option<SocketAddr>, window: usize) -> Self {
+        ReliableUdp {
+            socket,
+            window: window.max(1),
+            next_seq: 0,
+            outgoing: BTreeMap::new(),
+            recv_base: 0,
+            reorder: BTreeMap::new(),
+            inbox: VecDeque::new(),
+        }
+    }
+
+    async fn send_data(&self, seq: u64, payload: &[u8]) -> Result<(), Error> {
+        let mut datagram = Vec::with_capacity(9 + payload.len());
+        datagram.push(DATA);
+        datagram.extend_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    async fn send_ack(&self) -> Result<(), Error> {
+        let mut datagram = Vec::with_capacity(9);
+        datagram.push(DATA_ACK);
+        datagram.extend_from_slice(&self.recv_base.to_be_bytes());
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    async fn send_nak(&self, missing: &[u64]) -> Result<(), Error> {
+        let mut datagram = Vec::with_capacity(9 + missing.len() * 8);
+        datagram.push(DATA_NAK);
+        datagram.extend_from_slice(&(missing.len() as u64).to_be_bytes());
+        for seq in missing {
+            datagram.extend_from_slice(&seq.to_be_bytes());
+        }
+        self.socket.send(&datagram).await?;
+        Ok(())
+    }
+
+    async fn retransmit_all(&self) -> Result<(), Error> {
+        for (seq, payload) in &self.outgoing {
+            self.send_data(*seq, payload).await?;
+        }
+        Ok(())
+    }
+
+    // process one inbound datagram, updating send/receive state accordingly
+    async fn handle_datagram(&mut self, datagram: &[u8]) -> Result<(), Error> {
+        match datagram.first() {
+            Some(&DATA) => {
+                if datagram.len() < 9 {
+                    return Ok(());
+                }
+                let mut seq_bytes = [0u8; 8];
+                seq_bytes.copy_from_slice(&datagram[1..9]);
+                let seq = u64::from_be_bytes(seq_bytes);
+                let payload = datagram[9..].to_vec();
+                if seq == self.recv_base {
+                    self.inbox.extend(payload);
+                    self.recv_base += 1;
+                    // drain any buffered successors that are now in order
+                    while let Some(next) = self.reorder.remove(&self.recv_base) {
+                        self.inbox.extend(next);
+                        self.recv_base += 1;
+                    }
+                } else if seq > self.recv_base {
+                    self.reorder.entry(seq).or_insert(payload);
+                    let missing: Vec<u64> = (self.recv_base..seq)
+                        .filter(|s| !self.reorder.contains_key(s))
+                        .collect();
+                    if !missing.is_empty() {
+                        self.send_nak(&missing).await?;
+                    }
+                }
+                self.send_ack().await?;
+            }
+            Some(&DATA_ACK) if datagram.len() >= 9 => {
+                let mut ack_bytes = [0u8; 8];
+                ack_bytes.copy_from_slice(&datagram[1..9]);
+                let ack = u64::from_be_bytes(ack_bytes);
+                self.outgoing.retain(|seq, _| *seq >= ack);
+            }
+            Some(&DATA_NAK) if datagram.len() >= 9 => {
+                let mut count_bytes = [0u8; 8];
+                count_bytes.copy_from_slice(&datagram[1..9]);
+                let count = u64::from_be_bytes(count_bytes) as usize;
+                for index in 0..count {
+                    let start = 9 + index * 8;
+                    if start + 8 > datagram.len() {
+                        break;
+                    }
+                    let mut seq_bytes = [0u8; 8];
+                    seq_bytes.copy_from_slice(&datagram[start..start + 8]);
+                    let seq = u64::from_be_bytes(seq_bytes);
+                    if let Some(payload) = self.outgoing.get(&seq).cloned() {
+                        self.send_data(seq, &payload).await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // receive and process a single datagram, retransmitting on timeout; returns an
+    // error after more than `MAX_RETRIES` consecutive timeouts, whether or not any
+    // datagram is still unacknowledged. Any received datagram resets the count.
+    async fn pump(&mut self, retries: &mut usize) -> Result<(), Error> {
+        let mut datagram = vec![0u8; TRANSFER_BUFFER_SIZE + 16];
+        match timeout(Duration::from_millis(200), self.socket.recv(&mut datagram)).await {
+            Ok(Ok(len)) => {
+                *retries = 0;
+                self.handle_datagram(&datagram[..len]).await?;
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => {
+                // nothing arrived: nudge the peer and retransmit anything outstanding
+                if !self.outgoing.is_empty() {
+                    self.retransmit_all().await?;
+                }
+                self.send_ack().await?;
+                *retries += 1;
+                if *retries > MAX_RETRIES {
+                    Err(Error::msg("udp transfer timed out"))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut retries = 0;
+        for payload in data.chunks(TRANSFER_BUFFER_SIZE) {
+            // respect the in-flight window before emitting the next datagram
+            while self.outgoing.len() >= self.window {
+                self.pump(&mut retries).await?;
+            }
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.outgoing.insert(seq, payload.to_vec());
+            self.send_data(seq, payload).await?;
+        }
+        Ok(())
+    }
+
+    // block until every outstanding datagram has been acknowledged; called at the
+    // end of a send so a lost tail datagram is retransmitted instead of stranding
+    // the receiver after the socket closes.
+    async fn flush(&mut self) -> Result<(), Error> {
+        let mut retries = 0;
+        while !self.outgoing.is_empty() {
+            self.pump(&mut retries).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut retries = 0;
+        while self.inbox.len() < buffer.len() {
+            self.pump(&mut retries).await?;
+        }
+        for slot in buffer.iter_mut() {
+            *slot = self.inbox.pop_front().unwrap();
+        }
         Ok(())
-    } else {
-        Err(Error::msg("writing empty string is not allowed"))
     }
 }
 
-async fn write_file(
-    file_name: &str,
-    remote_name: &str,
-    stream: &mut TcpStream,
-) -> Result<(), Error> {
-    let file = File::open(file_name)?;
-    let mut len = file.metadata().unwrap().len();
-    let mut reader = BufReader::new(file);
-    let normalized_name = normalize_name(remote_name.to_string());
-    // write normalized filename
-    write_string(stream, &normalized_name).await?;
-    // write the length
-    write_u64(stream, len).await?;
-    // write chunks
-    let mut buffer = [0; BUFFER_SIZE];
-    while len > 0 {
-        let read_bytes = reader.read(&mut buffer)? as u64;
-        write_buffer(stream, &buffer, read_bytes).await?;
-        len -= read_bytes;
+// Unix permission bits, or 0 on platforms that have no such concept
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0
+    }
+}
+
+// modification time in whole seconds since the Unix epoch, or 0 if unavailable
+fn file_mtime(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// restore the transmitted permissions and modification time on the saved file
+fn apply_metadata(full_name: &str, mode: u32, mtime: u64) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if mode != 0 {
+            fs::set_permissions(full_name, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = mode;
+    }
+    if mtime != 0 {
+        set_file_mtime(full_name, FileTime::from_unix_time(mtime as i64, 0))?;
     }
     Ok(())
 }